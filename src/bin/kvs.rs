@@ -1,12 +1,29 @@
 extern crate structopt;
 
-use kvs::{KvStore, KvsError, Result};
+use kvs::{upgrade, Config, Engine, KvsError, Result};
 use std::env::current_dir;
+use std::ops::Bound;
 use std::process::exit;
+use std::str::FromStr;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = env!("CARGO_PKG_NAME"), author = env!("CARGO_PKG_AUTHORS"), about = env!("CARGO_PKG_DESCRIPTION"))]
+struct Opt {
+    #[structopt(
+        long = "engine",
+        help = "storage engine to use ('memory' persists nothing once this process exits, \
+                so set/get/rm/scan won't see each other's data across separate invocations)",
+        possible_values = &["kvs", "memory"],
+        default_value = "kvs"
+    )]
+    engine: EngineArg,
+
+    #[structopt(subcommand)]
+    cmd: Kvs,
+}
+
+#[derive(Debug, StructOpt)]
 enum Kvs {
     #[structopt(name = "set", about = "Set the value of a string key to a string")]
     Set {
@@ -25,25 +42,59 @@ enum Kvs {
         #[structopt(name = "KEY", required = true, help = "KEY to remove")]
         key: String,
     },
+    #[structopt(
+        name = "scan",
+        about = "Print all key/value pairs with keys in [START, END]"
+    )]
+    Scan {
+        #[structopt(name = "START", required = true, help = "start of the key range")]
+        start: String,
+        #[structopt(name = "END", required = true, help = "end of the key range")]
+        end: String,
+    },
+    #[structopt(
+        name = "upgrade",
+        about = "Migrate the on-disk log in the current directory to the latest format"
+    )]
+    Upgrade,
+}
+
+// Thin wrapper so structopt can parse `--engine` directly into an `Engine`.
+#[derive(Debug)]
+struct EngineArg(Engine);
+
+impl FromStr for EngineArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "kvs" => Ok(EngineArg(Engine::Kvs)),
+            "memory" => Ok(EngineArg(Engine::Memory)),
+            _ => Err(format!("unknown engine: {}", s)),
+        }
+    }
 }
 
 fn main() -> Result<()> {
-    match Kvs::from_args() {
+    let opt = Opt::from_args();
+    let config = Config::new(current_dir()?).engine(opt.engine.0);
+
+    match opt.cmd {
         Kvs::Set { key, val } => {
-            let mut store = KvStore::open(current_dir()?.as_path())?;
-            store.set(key, val)?;
+            let mut store = config.open()?;
+            store.set_str(key, val)?;
         }
         Kvs::Get { key } => {
-            let mut store = KvStore::open(current_dir()?.as_path())?;
-            if let Some(val) = store.get(key)? {
+            let mut store = config.open()?;
+            if let Some(val) = store.get_str(key)? {
                 println!("{}", val);
             } else {
                 println!("Key not found");
             }
         }
         Kvs::Remove { key } => {
-            let mut store = KvStore::open(current_dir()?.as_path())?;
-            match store.remove(key) {
+            let mut store = config.open()?;
+            match store.remove_str(key) {
                 Ok(()) => {}
                 Err(KvsError::KeyNotFound) => {
                     println!("Key not found");
@@ -52,6 +103,16 @@ fn main() -> Result<()> {
                 Err(e) => return Err(e),
             }
         }
+        Kvs::Scan { start, end } => {
+            let mut store = config.open()?;
+            for entry in store.scan_str(Bound::Included(start), Bound::Included(end))? {
+                let (key, val) = entry?;
+                println!("{} {}", key, val);
+            }
+        }
+        Kvs::Upgrade => {
+            upgrade(current_dir()?.as_path())?;
+        }
     }
     Ok(())
 }
@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+
+use engines::{is_valid_range, KvsEngine};
+use error::KvsError;
+use Result;
+
+/// `InMemoryStore` keeps key/value pairs in a `BTreeMap` with no disk I/O,
+/// losing all data once dropped. Useful for tests and ephemeral caches
+/// where durability isn't needed.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{InMemoryStore, KvsEngine, Result};
+/// # use std::env::current_dir;
+///
+/// fn main() -> Result<()> {
+///     let mut store = InMemoryStore::open(current_dir()?.as_path())?;
+///     store.set_str("key".to_owned(), "value".to_owned())?;
+///     let val = store.get_str("key".to_owned())?;
+///     assert_eq!(val, Some("value".to_owned()));
+///     Ok(())
+/// }
+/// ```
+#[derive(Default)]
+pub struct InMemoryStore {
+    map: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl KvsEngine for InMemoryStore {
+    /// Creates an empty `InMemoryStore`. `path` is accepted to satisfy
+    /// `KvsEngine` but is otherwise unused, since nothing is persisted to
+    /// disk.
+    fn open(_path: &Path) -> Result<Self> {
+        Ok(InMemoryStore::default())
+    }
+
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.map.insert(key, val);
+        Ok(())
+    }
+
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.map.get(&key).cloned())
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        self.map.remove(&key).ok_or(KvsError::KeyNotFound)?;
+        Ok(())
+    }
+
+    fn scan(
+        &mut self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> Result<Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_>> {
+        if !is_valid_range(&start, &end) {
+            return Ok(Box::new(::std::iter::empty()));
+        }
+        Ok(Box::new(
+            self.map
+                .range((start, end))
+                .map(|(key, val)| Ok((key.clone(), val.clone()))),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_with_reversed_bounds_is_empty_not_a_panic() {
+        let mut store = InMemoryStore::default();
+        store.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        store.set(b"z".to_vec(), b"2".to_vec()).unwrap();
+
+        let results: Vec<_> = store
+            .scan(
+                Bound::Included(b"z".to_vec()),
+                Bound::Included(b"a".to_vec()),
+            )
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert!(results.is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+//! Pluggable storage engines backing `kvs`.
+
+mod kvs;
+mod memory;
+
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+pub use self::kvs::{upgrade, KvStore, Snapshot};
+pub use self::memory::InMemoryStore;
+
+use Result;
+
+// LogID is used to compose log file name, i.e.,, <log_id>.log.
+pub(crate) type LogID = u64;
+// LogOffset represents a Command's offset resides in a log file.
+pub(crate) type LogOffset = u64;
+
+/// `KvsEngine` is the trait implemented by every storage backend kvs can
+/// use: the on-disk, log-structured `KvStore`, and the in-memory
+/// `InMemoryStore`.
+///
+/// Code that just needs to set/get/remove key/value pairs can be generic
+/// over `KvsEngine` rather than depending on a concrete backend. Keys and
+/// values are arbitrary bytes; `set_str`/`get_str`/`remove_str` are thin
+/// UTF-8 convenience wrappers for the common case of string data.
+pub trait KvsEngine {
+    /// Opens the engine, rooted at `path`.
+    fn open(path: &Path) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Sets the value of a key to a value.
+    ///
+    /// If the key already exists, the existing value will be overwritten.
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Removes a given key.
+    ///
+    /// Returns an error if the key does not exist.
+    fn remove(&mut self, key: Vec<u8>) -> Result<()>;
+
+    /// Iterates over the key/value pairs whose key falls within
+    /// `start..end`, in ascending key order.
+    fn scan(
+        &mut self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> Result<Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_>>;
+
+    /// Sets the value of a string key to a string.
+    ///
+    /// Convenience wrapper around `set` for callers dealing in UTF-8
+    /// strings rather than raw bytes.
+    fn set_str(&mut self, key: String, val: String) -> Result<()> {
+        self.set(key.into_bytes(), val.into_bytes())
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns an error if the stored value is not valid UTF-8.
+    fn get_str(&mut self, key: String) -> Result<Option<String>> {
+        match self.get(key.into_bytes())? {
+            Some(val) => Ok(Some(String::from_utf8(val)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes a given string key.
+    ///
+    /// Returns an error if the key does not exist.
+    fn remove_str(&mut self, key: String) -> Result<()> {
+        self.remove(key.into_bytes())
+    }
+
+    /// Iterates over the key/value pairs whose key falls within
+    /// `start..end`, in ascending key order.
+    ///
+    /// Returns an error if a key or value in range is not valid UTF-8.
+    fn scan_str(
+        &mut self,
+        start: Bound<String>,
+        end: Bound<String>,
+    ) -> Result<Box<dyn Iterator<Item = Result<(String, String)>> + '_>> {
+        let iter = self.scan(bound_into_bytes(start), bound_into_bytes(end))?;
+        Ok(Box::new(iter.map(|item| {
+            let (key, val) = item?;
+            Ok((String::from_utf8(key)?, String::from_utf8(val)?))
+        })))
+    }
+}
+
+fn bound_into_bytes(bound: Bound<String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(s) => Bound::Included(s.into_bytes()),
+        Bound::Excluded(s) => Bound::Excluded(s.into_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// Whether (start, end) is safe to pass to `BTreeMap::range` without it
+// panicking: it panics if `start > end`, or if `start == end` and both
+// bounds are `Excluded`. Engines call this before scanning so a reversed
+// or degenerate range (e.g. from `kvs scan zzz aaa`) yields an empty scan
+// rather than crashing the process.
+pub(crate) fn is_valid_range(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    fn key(bound: &Bound<Vec<u8>>) -> Option<&Vec<u8>> {
+        match bound {
+            Bound::Included(k) | Bound::Excluded(k) => Some(k),
+            Bound::Unbounded => None,
+        }
+    }
+    match (key(start), key(end)) {
+        (Some(s), Some(e)) if s > e => false,
+        (Some(s), Some(e)) if s == e => match (start, end) {
+            (Bound::Excluded(_), Bound::Excluded(_)) => false,
+            _ => true,
+        },
+        _ => true,
+    }
+}
+
+/// Selects which `KvsEngine` backend a `Config` should open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// The on-disk, log-structured engine (`KvStore`).
+    Kvs,
+    /// The in-memory engine (`InMemoryStore`), useful for tests and
+    /// ephemeral caches.
+    Memory,
+}
+
+impl Default for Engine {
+    fn default() -> Engine {
+        Engine::Kvs
+    }
+}
+
+/// Builder that selects and opens a `KvsEngine` backend.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{Config, Engine, Result};
+/// # use std::env::current_dir;
+///
+/// fn main() -> Result<()> {
+///     let mut store = Config::new(current_dir()?).engine(Engine::Memory).open()?;
+///     store.set_str("key".to_owned(), "value".to_owned())?;
+///     Ok(())
+/// }
+/// ```
+pub struct Config {
+    root_dir: PathBuf,
+    engine: Engine,
+}
+
+impl Config {
+    /// Creates a `Config` rooted at `path`, defaulting to the `KvStore`
+    /// engine.
+    pub fn new(path: impl Into<PathBuf>) -> Config {
+        Config {
+            root_dir: path.into(),
+            engine: Engine::default(),
+        }
+    }
+
+    /// Selects which engine `open` should construct.
+    pub fn engine(mut self, engine: Engine) -> Config {
+        self.engine = engine;
+        self
+    }
+
+    /// Opens the selected engine.
+    pub fn open(self) -> Result<Box<dyn KvsEngine>> {
+        match self.engine {
+            Engine::Kvs => Ok(Box::new(KvStore::open(&self.root_dir)?)),
+            Engine::Memory => Ok(Box::new(InMemoryStore::open(&self.root_dir)?)),
+        }
+    }
+}
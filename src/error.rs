@@ -0,0 +1,69 @@
+use failure::Fail;
+use std::io;
+use std::result;
+use std::string::FromUtf8Error;
+
+use engines::{LogID, LogOffset};
+
+/// Error types used by `KvStore`.
+#[derive(Debug, Fail)]
+pub enum KvsError {
+    /// IO error.
+    #[fail(display = "{}", _0)]
+    Io(#[cause] io::Error),
+    /// Serialization or deserialization error.
+    #[fail(display = "{}", _0)]
+    Serde(#[cause] serde_json::Error),
+    /// A value (or key) read through the `_str` convenience methods was not
+    /// valid UTF-8.
+    #[fail(display = "{}", _0)]
+    Utf8(#[cause] FromUtf8Error),
+    /// Removing non-exist key error.
+    #[fail(display = "Key not found")]
+    KeyNotFound,
+    /// Unexpected command type error, which indicates either a corrupted log
+    /// or a program bug.
+    #[fail(display = "Unexpected command type")]
+    UnexpectedCommandType,
+    /// A log record's CRC32 did not match its payload, indicating the log
+    /// file was corrupted rather than simply torn by a partial write.
+    #[fail(display = "corrupt log {}.log at offset {}", log_id, offset)]
+    CorruptLog {
+        /// Id of the log file containing the corrupt record.
+        log_id: LogID,
+        /// Byte offset of the corrupt record's header within the log file.
+        offset: LogOffset,
+    },
+    /// A log file is missing the current `LOG_MAGIC`/`LOG_VERSION` header,
+    /// either because it predates versioning or was written by a future,
+    /// incompatible version of kvs. Run `kvs upgrade` to migrate it.
+    #[fail(
+        display = "log {}.log is not in the current format; run `kvs upgrade` first",
+        log_id
+    )]
+    UnsupportedLogVersion {
+        /// Id of the log file missing a valid header.
+        log_id: LogID,
+    },
+}
+
+/// Result<T> is the custom error type for `KvStore`.
+pub type Result<T> = result::Result<T, KvsError>;
+
+impl From<io::Error> for KvsError {
+    fn from(err: io::Error) -> KvsError {
+        KvsError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(err: serde_json::Error) -> KvsError {
+        KvsError::Serde(err)
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> KvsError {
+        KvsError::Utf8(err)
+    }
+}